@@ -3,58 +3,176 @@
 #![no_std]
 #![no_main]
 
+mod dma;
+
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
-use heapless::spsc::Queue;
+use critical_section::with;
+use dma::DmaRingBuffer;
 use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
-use stm32l4::stm32l4x2::{interrupt, Interrupt, Peripherals, SPI1, USART2};
+use stm32l4::stm32l4x2::{interrupt, Interrupt, Peripherals, DMA1, SPI1, USART2};
 
-static mut USART2_PERIPHERAL: Option<USART2> = None;
-static mut SPI1_PERIPHERAL: Option<SPI1> = None;
-/// Queue of bytes to send over SPI1, received over USART2
-static mut TX_BUFFER: Option<Queue<u16, 16>> = None;
+static USART2_PERIPHERAL: Mutex<RefCell<Option<USART2>>> = Mutex::new(RefCell::new(None));
+static SPI1_PERIPHERAL: Mutex<RefCell<Option<SPI1>>> = Mutex::new(RefCell::new(None));
+static DMA1_PERIPHERAL: Mutex<RefCell<Option<DMA1>>> = Mutex::new(RefCell::new(None));
+/// Ring buffer fed by the USART2 RX DMA channel running in circular mode
+static RX_RING: Mutex<RefCell<DmaRingBuffer<64>>> = Mutex::new(RefCell::new(DmaRingBuffer::new()));
 
-#[interrupt]
-fn USART2() {
-    // SAFETY: race condition where USART2_PERIPHERAL can be accessed before being set
-    let usart2 = unsafe { USART2_PERIPHERAL.as_mut() }.unwrap();
-    let spi1 = unsafe { SPI1_PERIPHERAL.as_mut() }.unwrap();
-    let tx_buffer = unsafe { TX_BUFFER.as_mut() }.unwrap();
-
-    if usart2.isr.read().rxne().bit_is_set() {
-        // Read data, this clears RXNE
-        let received_byte = usart2.rdr.read().rdr().bits();
-
-        // Queue byte, do nothing if queue is full
-        if tx_buffer.enqueue(received_byte).is_ok() {
-            // Enable TXE interrupt as buffer is now non-empty
-            // usart2.cr1.modify(|_, w| w.txeie().enabled());
-            spi1.cr2.modify(|_, w| w.txeie().set_bit());
-            spi1.cr1.modify(|_, w| w.spe().enabled());
-        }
+/// System clock frequency after `clock_setup` runs. USART2 and SPI1 both hang off buses
+/// that are undivided from SYSCLK, so BRR and the SPI baud-rate divider are derived from
+/// this single constant rather than hard-coded against the 4 MHz MSI reset default.
+const SYSCLK_HZ: u32 = 80_000_000;
+
+/// Target SPI1 clock: fast enough to keep the bridge's SPI leg well ahead of USART2's
+/// 9600 baud, without assuming what SYSCLK happens to be.
+const SPI1_TARGET_HZ: u32 = 10_000_000;
+
+/// Smallest `BR` prescaler (divisor `2^(br+1)`) that keeps SPI1's clock at or below
+/// `target_hz`, given an input clock of `sysclk_hz`.
+fn spi1_br_bits(sysclk_hz: u32, target_hz: u32) -> u8 {
+    let mut br = 0u8;
+    while br < 7 && (sysclk_hz >> (br + 1)) > target_hz {
+        br += 1;
+    }
+    br
+}
+
+/// Raise the core out of the 4 MHz MSI reset default: scale the voltage regulator up to
+/// its highest-performance range, then enable and switch to a PLL running off the MSI.
+fn clock_setup(rcc: &stm32l4::stm32l4x2::RCC, pwr: &stm32l4::stm32l4x2::PWR, flash: &stm32l4::stm32l4x2::FLASH) {
+    // Enable the PWR peripheral clock so its registers are accessible
+    rcc.apb1enr1.modify(|_, w| w.pwren().set_bit());
+
+    // Scale the voltage regulator to range 1 (high performance) before raising SYSCLK
+    pwr.cr1.modify(|_, w| unsafe { w.vos().bits(0b01) });
+    while pwr.sr2.read().vosf().bit_is_set() {}
+
+    // Flash needs more wait states once SYSCLK exceeds 16 MHz at range-1 voltage
+    flash.acr.modify(|_, w| unsafe { w.latency().bits(4) });
+    while flash.acr.read().latency().bits() != 4 {}
+
+    // PLL: MSI (4 MHz) / PLLM(1) * PLLN(40) / PLLR(2) = 80 MHz
+    rcc.cr.modify(|_, w| w.pllon().clear_bit());
+    while rcc.cr.read().pllrdy().bit_is_set() {}
+    rcc.pllcfgr.write(|w| unsafe {
+        w.pllsrc()
+            .bits(0b01) // MSI
+            .pllm()
+            .bits(0)
+            .plln()
+            .bits(40)
+            .pllr()
+            .bits(0b00) // /2
+            .pllren()
+            .set_bit()
+    });
+    rcc.cr.modify(|_, w| w.pllon().set_bit());
+    while rcc.cr.read().pllrdy().bit_is_clear() {}
+
+    // Switch SYSCLK source to the PLL and wait for the switch to take effect
+    rcc.cfgr.modify(|_, w| unsafe { w.sw().bits(0b11) });
+    while rcc.cfgr.read().sws().bits() != 0b11 {}
+}
+
+/// Point SPI1's TX DMA channel (DMA1 channel 3) at `bytes` and kick off a one-shot
+/// transfer. `bytes` lives inside `RX_RING`, which the USART2 RX DMA channel only
+/// advances past after this transfer is queued, so the source data stays valid. Callers
+/// must only call this once channel 3's previous transfer has completed (see
+/// `DMA1_CH6`/`DMA1_CH3` below) -- reprogramming `cmar3`/`cndtr3` while channel 3 is
+/// still reading the old slice out of `RX_RING` would race the in-flight DMA read.
+fn spi1_dma_tx(dma1: &DMA1, spi1: &SPI1, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
     }
-    if usart2.isr.read().ore().bit_is_set() {
-        usart2.icr.write(|w| w.orecf().set_bit());
+    dma1.ccr3.modify(|_, w| w.en().clear_bit());
+    dma1.cmar3.write(|w| unsafe { w.ma().bits(bytes.as_ptr() as u32) });
+    dma1.cndtr3.write(|w| unsafe { w.ndt().bits(bytes.len() as u16) });
+    dma1.ccr3.modify(|_, w| w.en().set_bit());
+    spi1.cr1.modify(|_, w| w.spe().enabled());
+}
+
+/// Hand whatever USART2_RX has written into `RX_RING` since the last call off to SPI1's
+/// TX DMA channel -- but only if channel 3 isn't still draining the previous slice out
+/// from under us. Shared by `DMA1_CH6` (half/full RX DMA transfer), `DMA1_CH3` (SPI1 TX
+/// complete), and `USART2` (idle line), so a short input that falls well short of a
+/// half/full transfer still gets flushed out once the line goes idle instead of sitting
+/// in `RX_RING` until 32+ more bytes arrive.
+fn flush_rx_ring(dma1: &DMA1, spi1: &SPI1, rx_ring: &mut DmaRingBuffer<64>) {
+    if dma1.ccr3.read().en().bit_is_set() {
+        return;
     }
+    let remaining = dma1.cndtr6.read().ndt().bits();
+    let bytes = rx_ring.take_slice(remaining);
+    spi1_dma_tx(dma1, spi1, bytes);
+}
+
+#[interrupt]
+fn DMA1_CH6() {
+    with(|cs| {
+        let mut dma1 = DMA1_PERIPHERAL.borrow(cs).borrow_mut();
+        let dma1 = dma1.as_mut().unwrap();
+        let spi1 = SPI1_PERIPHERAL.borrow(cs).borrow();
+        let spi1 = spi1.as_ref().unwrap();
+        let mut rx_ring = RX_RING.borrow(cs).borrow_mut();
+
+        // Clear whichever of half-transfer/transfer-complete fired
+        dma1.ifcr.write(|w| w.htif6().set_bit().tcif6().set_bit());
+
+        // Channel 3 is still draining the slice from the last time it was kicked off, and
+        // that slice lives inside RX_RING -- leave read_pos where it is rather than
+        // taking (and losing) a new slice out from under it. DMA1_CH3 picks up whatever
+        // accumulated here once channel 3 goes idle.
+        flush_rx_ring(dma1, spi1, &mut rx_ring);
+    });
 }
 
 #[interrupt]
-fn SPI1() {
-    let spi1 = unsafe { SPI1_PERIPHERAL.as_mut() }.unwrap();
-    let tx_buffer = unsafe { TX_BUFFER.as_mut() }.unwrap();
-
-    if spi1.sr.read().txe().bit_is_set() {
-        match tx_buffer.dequeue() {
-            Some(byte) => {
-                spi1.dr.write(|w| w.dr().bits(byte));
-                while spi1.sr.read().bsy().bit_is_set() {}
-                spi1.cr1.modify(|_, w| w.spe().disabled());
-                if tx_buffer.is_empty() {
-                    spi1.cr2.modify(|_, w| w.txeie().clear_bit());
-                }
-            }
-            None => spi1.cr2.modify(|_, w| w.txeie().clear_bit()),
+fn DMA1_CH3() {
+    with(|cs| {
+        let mut dma1 = DMA1_PERIPHERAL.borrow(cs).borrow_mut();
+        let dma1 = dma1.as_mut().unwrap();
+        let spi1 = SPI1_PERIPHERAL.borrow(cs).borrow();
+        let spi1 = spi1.as_ref().unwrap();
+        let mut rx_ring = RX_RING.borrow(cs).borrow_mut();
+
+        dma1.ifcr.write(|w| w.tcif3().set_bit());
+        // Only disable SPE once SPI1 is done shifting out the last byte DMA handed it --
+        // but don't spin on `bsy` with interrupts masked to wait for that. If it's still
+        // set, just leave SPE enabled; the next DMA1_CH3 run will see it clear and catch
+        // up. Re-arming the TX DMA below doesn't depend on SPE being off first, so
+        // nothing is lost by deferring the disable.
+        if spi1.sr.read().bsy().bit_is_clear() {
+            spi1.cr1.modify(|_, w| w.spe().disabled());
         }
-    }
+
+        // Flush whatever DMA1_CH6 queued up in RX_RING while channel 3 was busy.
+        flush_rx_ring(dma1, spi1, &mut rx_ring);
+    });
+}
+
+#[interrupt]
+fn USART2() {
+    with(|cs| {
+        let mut dma1 = DMA1_PERIPHERAL.borrow(cs).borrow_mut();
+        let dma1 = dma1.as_mut().unwrap();
+        let spi1 = SPI1_PERIPHERAL.borrow(cs).borrow();
+        let spi1 = spi1.as_ref().unwrap();
+        let usart2 = USART2_PERIPHERAL.borrow(cs).borrow();
+        let usart2 = usart2.as_ref().unwrap();
+        let mut rx_ring = RX_RING.borrow(cs).borrow_mut();
+
+        if usart2.isr.read().ore().bit_is_set() {
+            usart2.icr.write(|w| w.orecf().set_bit());
+        }
+        if usart2.isr.read().idle().bit_is_set() {
+            usart2.icr.write(|w| w.idlecf().set_bit());
+            // The line just went idle: flush whatever's accumulated in RX_RING even if
+            // it's short of a half/full DMA transfer, so a short interactive input isn't
+            // stuck waiting for 32+ more bytes to arrive before it's echoed back.
+            flush_rx_ring(dma1, spi1, &mut rx_ring);
+        }
+    });
 }
 
 #[entry]
@@ -63,10 +181,13 @@ fn main() -> ! {
 
     let dp = Peripherals::take().unwrap();
 
-    // Enable peripheral clocks - GPIOA, USART2, SPI1
+    clock_setup(&dp.RCC, &dp.PWR, &dp.FLASH);
+
+    // Enable peripheral clocks - GPIOA, USART2, SPI1, DMA1
     dp.RCC.ahb2enr.write(|w| w.gpioaen().set_bit());
     dp.RCC.apb1enr1.write(|w| w.usart2en().enabled());
     dp.RCC.apb2enr.write(|w| w.spi1en().set_bit());
+    dp.RCC.ahb1enr.write(|w| w.dma1en().set_bit());
 
     // USART2: A2 (TX), A3 (RX) as AF 7
     // SPI1: A4 (NSS), A5 (SCK), A6 (MISO), A7 (MOSI) as AF 5
@@ -114,28 +235,81 @@ fn main() -> ! {
             .af5()
     });
 
-    // USART2: Configure baud rate 9600
-    dp.USART2.brr.write(|w| unsafe { w.bits(417) }); // 4Mhz / 9600 approx. 417
+    // USART2: Configure baud rate 9600 from the PLL-derived SYSCLK, rather than the old
+    // 4 MHz-assuming magic 417
+    dp.USART2
+        .brr
+        .write(|w| unsafe { w.bits((SYSCLK_HZ / 9600) as u16) });
 
-    // SPI1: enable hardware SS, master mode, baud rate of fpclk/2
+    // SPI1: enable hardware SS, master mode, baud rate derived from SYSCLK_HZ rather
+    // than a magic divider that assumed the old 4 MHz MSI default
     dp.SPI1
         .cr2
-        .write(|w| unsafe { w.ds().bits(7).ssoe().enabled() });
-    dp.SPI1.cr1.write(|w| w.br().bits(2).mstr().set_bit());
+        .write(|w| unsafe { w.ds().bits(7).ssoe().enabled().txdmaen().set_bit() });
+    dp.SPI1
+        .cr1
+        .write(|w| w.br().bits(spi1_br_bits(SYSCLK_HZ, SPI1_TARGET_HZ)).mstr().set_bit());
 
-    // Enable USART, receiver and RXNE interrupt
+    // Enable USART, receiver, overrun and idle-line interrupts, and RX DMA requests
     dp.USART2
         .cr1
-        .write(|w| w.re().set_bit().ue().set_bit().rxneie().set_bit());
-
-    unsafe {
-        TX_BUFFER = Some(Queue::default());
-        // Unmask NVIC USART2 global interrupt
-        cortex_m::peripheral::NVIC::unmask(Interrupt::SPI1);
-        cortex_m::peripheral::NVIC::unmask(Interrupt::USART2);
-        SPI1_PERIPHERAL = Some(dp.SPI1);
-        USART2_PERIPHERAL = Some(dp.USART2);
-    }
+        .write(|w| w.re().set_bit().ue().set_bit().idleie().set_bit());
+    dp.USART2.cr3.write(|w| w.dmar().set_bit().eie().set_bit());
+
+    // Base STM32L4x2 parts have no DMAMUX -- DMA1 request routing is fixed per channel
+    // via CSELR nibbles instead. C6S=2 selects USART2_RX on channel 6, C3S=1 selects
+    // SPI1_TX on channel 3 (see the DMA1 request mapping table in the reference manual).
+    dp.DMA1.cselr.write(|w| unsafe { w.c6s().bits(2).c3s().bits(1) });
+
+    with(|cs| {
+        let rx_ring_ptr = RX_RING.borrow(cs).borrow_mut().as_mut_ptr();
+
+        // DMA1 channel 6: USART2_RX -> RX_RING, circular, byte-sized, half/full interrupts
+        dp.DMA1
+            .cpar6
+            .write(|w| w.pa().bits(&dp.USART2.rdr as *const _ as u32));
+        dp.DMA1.cmar6.write(|w| w.ma().bits(rx_ring_ptr as u32));
+        dp.DMA1.cndtr6.write(|w| w.ndt().bits(64));
+        dp.DMA1.ccr6.write(|w| {
+            w.circ()
+                .set_bit()
+                .minc()
+                .set_bit()
+                .pinc()
+                .clear_bit()
+                .dir()
+                .clear_bit()
+                .htie()
+                .set_bit()
+                .tcie()
+                .set_bit()
+                .en()
+                .set_bit()
+        });
+
+        // DMA1 channel 3: RX_RING -> SPI1_TX, one-shot, re-armed by `flush_rx_ring`
+        dp.DMA1.cpar3.write(|w| w.pa().bits(&dp.SPI1.dr as *const _ as u32));
+        dp.DMA1.ccr3.write(|w| {
+            w.minc()
+                .set_bit()
+                .pinc()
+                .clear_bit()
+                .dir()
+                .set_bit()
+                .tcie()
+                .set_bit()
+        });
+
+        // Unmask NVIC interrupts
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(Interrupt::DMA1_CH6);
+            cortex_m::peripheral::NVIC::unmask(Interrupt::DMA1_CH3);
+            cortex_m::peripheral::NVIC::unmask(Interrupt::USART2);
+        }
+        DMA1_PERIPHERAL.borrow(cs).replace(Some(dp.DMA1));
+        SPI1_PERIPHERAL.borrow(cs).replace(Some(dp.SPI1));
+        USART2_PERIPHERAL.borrow(cs).replace(Some(dp.USART2));
+    });
 
     #[allow(clippy::empty_loop)]
     loop {}
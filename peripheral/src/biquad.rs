@@ -0,0 +1,72 @@
+//! Fixed-point Direct-Form-I biquad IIR filter, driven one sample at a time by the
+//! structured JSON protocol's `sample`/`coeffs` commands.
+//!
+//! Coefficients are Q-format integers scaled by `1 << SHIFT`: a real coefficient `c` is
+//! stored as `round(c * (1 << SHIFT))`. Samples and the filter history are plain
+//! integers in sample units.
+
+/// Fractional bits the coefficients are scaled by.
+pub const SHIFT: u32 = 14;
+
+const OUTPUT_MIN: i64 = i32::MIN as i64;
+const OUTPUT_MAX: i64 = i32::MAX as i64;
+
+/// Coefficients `[b0, b1, b2, a1, a2]`, Q-format scaled by `1 << SHIFT`.
+pub type Coefficients = [i32; 5];
+
+/// A gentle low-pass response, used until a "load coefficients" command overrides it.
+pub const DEFAULT_LOWPASS: Coefficients = [4096, 8192, 4096, 21299, -9830];
+
+pub struct Biquad {
+    coeffs: Coefficients,
+    x1: i32,
+    x2: i32,
+    y1: i32,
+    y2: i32,
+}
+
+impl Biquad {
+    pub const fn new() -> Self {
+        Self {
+            coeffs: DEFAULT_LOWPASS,
+            x1: 0,
+            x2: 0,
+            y1: 0,
+            y2: 0,
+        }
+    }
+
+    /// Replace the coefficient table and reset history, so the new response starts from
+    /// a clean state rather than mixing in samples filtered by the old one.
+    pub fn load_coefficients(&mut self, coeffs: Coefficients) {
+        self.coeffs = coeffs;
+        self.x1 = 0;
+        self.x2 = 0;
+        self.y1 = 0;
+        self.y2 = 0;
+    }
+
+    /// `acc = b0*x0 + b1*x1 + b2*x2 + a1*y1 + a2*y2`, rescaled by `SHIFT` and clamped to
+    /// `i32` range, then the history is shifted for the next sample.
+    pub fn process(&mut self, x0: i32) -> i32 {
+        let [b0, b1, b2, a1, a2] = self.coeffs;
+        let acc: i64 = b0 as i64 * x0 as i64
+            + b1 as i64 * self.x1 as i64
+            + b2 as i64 * self.x2 as i64
+            + a1 as i64 * self.y1 as i64
+            + a2 as i64 * self.y2 as i64;
+        let y0 = (acc >> SHIFT).clamp(OUTPUT_MIN, OUTPUT_MAX) as i32;
+
+        self.x2 = self.x1;
+        self.x1 = x0;
+        self.y2 = self.y1;
+        self.y1 = y0;
+        y0
+    }
+}
+
+impl Default for Biquad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,37 @@
+//! Ring buffer backed by a DMA channel that fills it from start to end, pass after pass.
+//!
+//! The DMA peripheral is the only thing that ever writes into the backing buffer: the
+//! channel's remaining-transfer count (`BNDT`) tells us where its write pointer
+//! currently sits, so we can hand out the newly-written bytes as contiguous slices
+//! without the CPU copying anything itself.
+
+pub struct DmaRingBuffer<const N: usize> {
+    buffer: [u8; N],
+    read_pos: usize,
+}
+
+impl<const N: usize> DmaRingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            read_pos: 0,
+        }
+    }
+
+    /// Address to program into the DMA channel's memory-address register.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.as_mut_ptr()
+    }
+
+    /// Returns the bytes the DMA channel has written since the last call, given its
+    /// current remaining-transfer count. If the channel's write pointer has wrapped
+    /// past the end of the buffer, only the bytes up to the end are returned; the rest
+    /// are picked up on the next call once `read_pos` has wrapped to 0.
+    pub fn take_slice(&mut self, remaining_transfers: u16) -> &[u8] {
+        let write_pos = N - remaining_transfers as usize;
+        let start = self.read_pos;
+        let end = if write_pos >= start { write_pos } else { N };
+        self.read_pos = if end == N { 0 } else { end };
+        &self.buffer[start..end]
+    }
+}
@@ -3,182 +3,565 @@
 #![no_std]
 #![no_main]
 
+mod biquad;
+mod dma;
+mod protocol;
+
+use biquad::Biquad;
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
-use heapless::{spsc::Queue, String};
+use critical_section::with;
+use dma::DmaRingBuffer;
+use heapless::{String, Vec};
 use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
-use stm32u5::stm32u575::{interrupt, Interrupt, Peripherals, SPI1, USART2};
+use stm32u5::stm32u575::{interrupt, Interrupt, Peripherals, GPDMA1, SPI1, USART2};
+
+static USART2_PERIPHERAL: Mutex<RefCell<Option<USART2>>> = Mutex::new(RefCell::new(None));
+static SPI1_PERIPHERAL: Mutex<RefCell<Option<SPI1>>> = Mutex::new(RefCell::new(None));
+static GPDMA1_PERIPHERAL: Mutex<RefCell<Option<GPDMA1>>> = Mutex::new(RefCell::new(None));
+static CALCULATOR: Mutex<RefCell<CalculatorStateMachine>> = Mutex::new(RefCell::new(CalculatorStateMachine::new()));
+/// Streaming IIR filter driven by the JSON protocol's `sample`/`coeffs` commands and by
+/// raw samples while `STREAM_MODE` is enabled
+static BIQUAD: Mutex<RefCell<Biquad>> = Mutex::new(RefCell::new(Biquad::new()));
+/// Size of the SPI1 RX GPDMA channel's block transfer, i.e. how many bytes it writes
+/// into `RX_RING` before a full-transfer interrupt re-arms it. Kept small (rather than
+/// matching the 128-byte USART2 TX staging capacity) so a half-transfer interrupt -- the
+/// only thing that flushes a response out for input shorter than a full SPI frame --
+/// fires after 8 bytes instead of 64, bounding how long a short interactive
+/// input like "2+3=" sits unprocessed.
+const RX_RING_LEN: usize = 16;
+
+/// Ring buffer fed by the SPI1 RX GPDMA channel
+static RX_RING: Mutex<RefCell<DmaRingBuffer<RX_RING_LEN>>> = Mutex::new(RefCell::new(DmaRingBuffer::new()));
+/// Accumulates a `{...}` line for the structured JSON command mode; empty when bytes
+/// are being handled by the digit/operator byte grammar instead
+static JSON_LINE: Mutex<RefCell<String<128>>> = Mutex::new(RefCell::new(String::new()));
+/// Set while USART2's TX GPDMA channel is draining a response out of `TX_STAGING`, so
+/// `RX_RING` isn't drained into `TX_STAGING` again until it's free. Cleared by
+/// `GPDMA1_CH1` (the channel's transfer-complete interrupt).
+static TX_BUSY: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
+/// Whether raw (non-JSON) bytes are being treated as comma/newline-terminated integer
+/// samples for the streaming biquad filter, instead of the digit/operator calculator
+/// grammar. Toggled by the JSON protocol's `{"mode":"stream"}`/`{"mode":"calc"}` commands.
+static STREAM_MODE: Mutex<RefCell<bool>> = Mutex::new(RefCell::new(false));
+/// In-progress streaming-mode sample, accumulated one digit at a time
+static SAMPLE: Mutex<RefCell<SampleAccumulator>> = Mutex::new(RefCell::new(SampleAccumulator::new()));
+/// Staging buffer for the responses sent out over the USART2 TX GPDMA channel, back to
+/// back: JSON `Response` lines, echoed tokens, `=` followed by the result (up to 20
+/// characters) or `ERR` and a CRLF, or filtered streaming-mode samples and a CRLF
+static TX_STAGING: Mutex<RefCell<[u8; 128]>> = Mutex::new(RefCell::new([0; 128]));
+
+/// System clock frequency after `clock_setup` runs. USART2's BRR is derived from this
+/// constant instead of assuming the 4 MHz MSI reset default.
+const SYSCLK_HZ: u32 = 160_000_000;
+
+/// Raise the core out of the 4 MHz MSI reset default: scale the voltage regulator up to
+/// range 0 (highest performance), then enable and switch to PLL1 running off the MSI.
+fn clock_setup(rcc: &stm32u5::stm32u575::RCC, pwr: &stm32u5::stm32u575::PWR, flash: &stm32u5::stm32u575::FLASH) {
+    // Enable the PWR peripheral clock so its registers are accessible
+    rcc.ahb3enr().modify(|_, w| w.pwren().set_bit());
+
+    // Scale the voltage regulator to range 0 (highest performance) before raising SYSCLK
+    pwr.vosr().modify(|_, w| unsafe { w.vos().bits(0b11) });
+    while pwr.vosr().read().vosrdy().bit_is_clear() {}
+
+    // Flash needs more wait states once SYSCLK exceeds 32 MHz at range-0 voltage
+    flash.acr().modify(|_, w| unsafe { w.latency().bits(5) });
+    while flash.acr().read().latency().bits() != 5 {}
 
-static mut USART2_PERIPHERAL: Option<USART2> = None;
-static mut SPI1_PERIPHERAL: Option<SPI1> = None;
-static mut BUFFER: Option<Queue<u16, 16>> = None;
-static mut CALCULATOR: Option<CalculatorStateMachine> = None;
+    // PLL1: MSI (4 MHz) / PLLM(1) * PLLN(80) / PLLR(2) = 160 MHz
+    rcc.cr().modify(|_, w| w.pll1on().clear_bit());
+    while rcc.cr().read().pll1rdy().bit_is_set() {}
+    rcc.pll1cfgr().write(|w| unsafe {
+        w.pll1src()
+            .bits(0b01) // MSI
+            .pll1m()
+            .bits(0)
+            .pll1rge()
+            .bits(0b11)
+    });
+    rcc.pll1divr().write(|w| unsafe { w.pll1n().bits(79).pll1r().bits(1) });
+    rcc.cr().modify(|_, w| w.pll1on().set_bit());
+    while rcc.cr().read().pll1rdy().bit_is_clear() {}
+    rcc.pll1cfgr().modify(|_, w| w.pll1ren().set_bit());
+
+    // Switch SYSCLK source to PLL1 and wait for the switch to take effect
+    rcc.cfgr1().modify(|_, w| unsafe { w.sw().bits(0b11) });
+    while rcc.cfgr1().read().sws().bits() != 0b11 {}
+}
+
+/// Precedence of a binary operator; higher binds tighter. `(` has no precedence of its
+/// own, it is only ever popped explicitly by a matching `)`.
+fn precedence(op: char) -> u8 {
+    match op {
+        '+' | '-' => 1,
+        '*' | '/' => 2,
+        _ => 0,
+    }
+}
 
-enum CalculatorState {
-    Num1,
-    Num2,
+/// Why a `transition`/`compute` call failed, so callers that need more than a pass/fail
+/// bit (the JSON protocol's `overflow`/`divide_by_zero`/`parse_error` statuses) can tell
+/// the causes apart instead of collapsing them all into one `None`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CalculatorError {
+    Overflow,
+    DivideByZero,
+    ParseError,
 }
 
-struct CalculatorStateMachine {
-    num1: String<4>,
-    num2: String<4>,
-    op: char,
-    state: CalculatorState,
+/// Shunting-yard expression evaluator: digits and operators stream in one character at
+/// a time via `transition`, tokenized onto an operand stack and an operator stack, and
+/// `=` triggers `compute` to unwind whatever is left. Uses `i64` accumulators with
+/// explicit `checked_*` arithmetic, so overflow and divide-by-zero set `error` instead
+/// of wrapping.
+pub(crate) struct CalculatorStateMachine {
+    operands: Vec<i64, 8>,
+    operators: Vec<char, 8>,
+    current: Option<i64>,
+    error: Option<CalculatorError>,
 }
 
 impl Default for CalculatorStateMachine {
     fn default() -> Self {
-        Self {
-            num1: String::new(),
-            num2: String::new(),
-            op: '+',
-            state: CalculatorState::Num1,
-        }
+        Self::new()
     }
 }
 
 impl CalculatorStateMachine {
+    pub(crate) const fn new() -> Self {
+        Self {
+            operands: Vec::new(),
+            operators: Vec::new(),
+            current: None,
+            error: None,
+        }
+    }
+
+    /// The cause of the most recent `transition`/`compute` failure, if any.
+    pub(crate) fn error(&self) -> Option<CalculatorError> {
+        self.error
+    }
+
+    /// Pop the top operator and apply it to the top two operands, pushing the result.
+    /// Returns false (and sets `error`) on stack underflow, overflow, or division by
+    /// zero.
+    fn apply_top(&mut self) -> bool {
+        let (Some(op), Some(rhs), Some(lhs)) = (self.operators.pop(), self.operands.pop(), self.operands.pop())
+        else {
+            self.error = Some(CalculatorError::ParseError);
+            return false;
+        };
+        let result: Result<i64, CalculatorError> = match op {
+            '+' => lhs.checked_add(rhs).ok_or(CalculatorError::Overflow),
+            '-' => lhs.checked_sub(rhs).ok_or(CalculatorError::Overflow),
+            '*' => lhs.checked_mul(rhs).ok_or(CalculatorError::Overflow),
+            '/' if rhs == 0 => Err(CalculatorError::DivideByZero),
+            '/' => lhs.checked_div(rhs).ok_or(CalculatorError::Overflow),
+            _ => Err(CalculatorError::ParseError),
+        };
+        match result {
+            Ok(value) if self.operands.push(value).is_ok() => true,
+            Ok(_) => {
+                self.error = Some(CalculatorError::Overflow);
+                false
+            }
+            Err(error) => {
+                self.error = Some(error);
+                false
+            }
+        }
+    }
+
+    /// Push the pending `current` number, if any, onto the operand stack.
+    fn flush_current(&mut self) {
+        if let Some(value) = self.current.take() {
+            if self.operands.push(value).is_err() {
+                self.error = Some(CalculatorError::Overflow);
+            }
+        }
+    }
+
     /// Receive input and go to next state. Returns: if state transition was valid.
     fn transition(&mut self, input: char) -> bool {
-        match self.state {
-            CalculatorState::Num1 => {
-                if self.num1.len() < self.num1.capacity() && input.is_ascii_digit() {
-                    let _ = self.num1.push(input); // Always succeeds because of check
-                    true
-                } else if ['+', '-', '*', '/'].contains(&input) {
-                    self.op = input;
-                    self.state = CalculatorState::Num2;
+        if self.error.is_some() {
+            return false;
+        }
+        if let Some(digit) = input.to_digit(10) {
+            let next = self
+                .current
+                .unwrap_or(0)
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(digit as i64));
+            match next {
+                Some(value) => {
+                    self.current = Some(value);
                     true
-                } else {
+                }
+                None => {
+                    self.error = Some(CalculatorError::Overflow);
                     false
                 }
             }
-            CalculatorState::Num2 => {
-                if self.num2.len() < self.num2.capacity() && input.is_ascii_digit() {
-                    let _ = self.num2.push(input); // Always succeeds because of check
-                    true
-                } else {
-                    false
+        } else if input == '(' {
+            if self.operators.push('(').is_err() {
+                self.error = Some(CalculatorError::ParseError);
+                return false;
+            }
+            true
+        } else if input == ')' {
+            self.flush_current();
+            while self.operators.last() != Some(&'(') {
+                if !self.apply_top() {
+                    return false;
+                }
+            }
+            self.operators.pop(); // discard the matching '('
+            true
+        } else if ['+', '-', '*', '/'].contains(&input) {
+            self.flush_current();
+            while self
+                .operators
+                .last()
+                .is_some_and(|&top| top != '(' && precedence(top) >= precedence(input))
+            {
+                if !self.apply_top() {
+                    return false;
                 }
             }
+            if self.operators.push(input).is_err() {
+                self.error = Some(CalculatorError::ParseError);
+                return false;
+            }
+            true
+        } else {
+            false
         }
     }
 
-    /// Compute arithmetic expression.
-    fn compute(&mut self) -> u32 {
-        let result = match &self.state {
-            CalculatorState::Num1 => self.num1.parse::<u32>().unwrap_or(0),
-
-            CalculatorState::Num2 => match self.op {
-                '+' => self
-                    .num1
-                    .parse::<u32>()
-                    .unwrap_or(0)
-                    .wrapping_add(self.num2.parse::<u32>().unwrap_or(0)),
-                '*' => self
-                    .num1
-                    .parse::<u32>()
-                    .unwrap_or(0)
-                    .wrapping_mul(self.num2.parse::<u32>().unwrap_or(0)),
-                '-' => self
-                    .num1
-                    .parse::<u32>()
-                    .unwrap_or(0)
-                    .saturating_sub(self.num2.parse::<u32>().unwrap_or(0)),
-                '/' => self
-                    .num1
-                    .parse::<u32>()
-                    .unwrap_or(0)
-                    .wrapping_div(self.num2.parse::<u32>().unwrap_or(1)),
-                _ => unreachable!(),
-            },
+    /// Unwind the remaining operators and return the final result, or the cause of
+    /// failure (overflow, divide-by-zero, or unbalanced parentheses). Resets the state
+    /// machine.
+    pub(crate) fn compute(&mut self) -> Result<i64, CalculatorError> {
+        self.flush_current();
+        while self.error.is_none() && !self.operators.is_empty() {
+            self.apply_top();
+        }
+        let result = match self.error {
+            Some(error) => Err(error),
+            None if self.operands.len() == 1 => Ok(self.operands.pop().unwrap()),
+            None => Err(CalculatorError::ParseError),
         };
-        // Reset state machine
-        self.state = CalculatorState::Num1;
-        self.num1.clear();
-        self.num2.clear();
+        self.operands.clear();
+        self.operators.clear();
+        self.current = None;
+        self.error = None;
         result
     }
 }
 
-#[interrupt]
-fn USART2() {
-    // SAFETY: race condition where USART2_PERIPHERAL can be accessed before being set
-    let usart2 = unsafe { USART2_PERIPHERAL.as_mut() }.unwrap();
-    let buffer = unsafe { BUFFER.as_mut() }.unwrap();
-
-    if usart2.isr_disabled().read().txfnf().bit_is_set() {
-        match buffer.dequeue() {
-            Some(byte) => {
-                usart2.tdr().write(|w| unsafe { w.tdr().bits(byte) });
-                if buffer.is_empty() {
-                    usart2.cr1_disabled().modify(|_, w| w.txfnfie().clear_bit());
-                }
+/// Format `value` into `out` as ASCII digits, with a leading `-` if negative and no
+/// leading zeroes. Returns the number of bytes written.
+fn format_i64(value: i64, out: &mut [u8]) -> usize {
+    let mut len = 0;
+    if value < 0 {
+        out[len] = b'-';
+        len += 1;
+    }
+    let mut magnitude = value.unsigned_abs();
+    let mut digits = [0u8; 20];
+    let mut digit_count = 0;
+    loop {
+        digits[digit_count] = b'0' + (magnitude % 10) as u8;
+        magnitude /= 10;
+        digit_count += 1;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    for &digit in digits[..digit_count].iter().rev() {
+        out[len] = digit;
+        len += 1;
+    }
+    len
+}
+
+/// Accumulates one streaming-mode sample at a time from raw ASCII digits (with an
+/// optional leading `-`), terminated by `,` or `\n`. Non-digit, non-sign, non-separator
+/// bytes are ignored rather than erroring, matching the fire-and-forget nature of a
+/// sample stream: there's no interactive terminal to report a parse error to.
+pub(crate) struct SampleAccumulator {
+    magnitude: i64,
+    negative: bool,
+    started: bool,
+}
+
+impl SampleAccumulator {
+    pub(crate) const fn new() -> Self {
+        Self {
+            magnitude: 0,
+            negative: false,
+            started: false,
+        }
+    }
+
+    /// Feed one byte in. Returns `Some(sample)` once `,`/`\n` completes one.
+    fn push(&mut self, byte: u8) -> Option<i64> {
+        match byte {
+            b'-' if !self.started => {
+                self.negative = true;
+                self.started = true;
+                None
             }
-            None => {
-                usart2.cr1_disabled().modify(|_, w| w.txfnfie().clear_bit());
+            b'0'..=b'9' => {
+                self.started = true;
+                self.magnitude = self.magnitude.saturating_mul(10).saturating_add((byte - b'0') as i64);
+                None
             }
+            b',' | b'\n' => {
+                let sample = if self.negative { -self.magnitude } else { self.magnitude };
+                *self = Self::new();
+                Some(sample)
+            }
+            _ => None,
         }
     }
 }
 
-#[interrupt]
-fn SPI1() {
-    let spi1 = unsafe { SPI1_PERIPHERAL.as_mut() }.unwrap();
-    let usart2 = unsafe { USART2_PERIPHERAL.as_mut() }.unwrap();
-    let calculator = unsafe { CALCULATOR.as_mut() }.unwrap();
-    let buffer = unsafe { BUFFER.as_mut() }.unwrap();
-
-    if spi1.spi_sr().read().rxp().bit_is_set() {
-        let received_byte = spi1.spi_rxdr().read().rxdr().bits() as u16;
-
-        // Compute calculator state machine if '=' received
-        if received_byte as u8 == b'=' {
-            let chars = int_to_chars(calculator.compute());
-            let mut non_zero_reached = false;
-            let _ = buffer.enqueue(b'=' as u16);
-
-            // Buffer chars
-            for (i, &c) in chars.iter().enumerate() {
-                if i == chars.len() - 1 && c == b'0' && !non_zero_reached {
-                    // If last char is 0 and no non-zeroes, output 0
-                    let _ = buffer.enqueue(c as u16);
-                } else if c == b'0' && !non_zero_reached {
-                    // Don't output leading zeroes
-                    continue;
-                } else if c > b'0' {
-                    non_zero_reached = true;
-                    let _ = buffer.enqueue(c as u16);
-                } else {
-                    // Output non-leading zeroes
-                    let _ = buffer.enqueue(c as u16);
-                }
+/// Worst case a `=` response can ever take: `'='` + up to 20 digits (`i64::MIN` has 19
+/// digits plus a sign) + CRLF. `staging` is a window into the shared `TX_STAGING`, and
+/// several of these responses can land back to back within one DMA transfer's worth of
+/// input, so every write must be checked against what's actually left rather than
+/// assuming the window is big enough.
+const MAX_RESPONSE_LEN: usize = 23;
+
+/// Worst case a streaming-mode sample response can take: up to 11 digits (`i32::MIN`
+/// has 10 digits plus a sign) + CRLF.
+const MAX_SAMPLE_RESPONSE_LEN: usize = 13;
+
+/// Run `sample` through `biquad` and write the filtered result plus a CRLF into
+/// `staging`. Drops the response (returns 0) if `staging` doesn't have room.
+fn build_sample_response(biquad: &mut Biquad, sample: i64, staging: &mut [u8]) -> usize {
+    if staging.len() < MAX_SAMPLE_RESPONSE_LEN {
+        return 0;
+    }
+    let y0 = biquad.process(sample as i32);
+    let mut len = format_i64(y0 as i64, staging);
+    staging[len] = 13; // CR
+    staging[len + 1] = 10; // LF
+    len += 2;
+    len
+}
+
+/// Write the calculator's response for `received` (echoed token, or `=` followed by the
+/// computed result, or `ERR` on overflow/divide-by-zero/unbalanced parentheses, and a
+/// CRLF) into `staging` and return the number of bytes written. Drops the response
+/// (returns 0) rather than writing past the end of `staging` if it doesn't have room.
+fn build_response(calculator: &mut CalculatorStateMachine, received: u8, staging: &mut [u8]) -> usize {
+    let mut len = 0;
+    if received == b'=' {
+        if staging.len() < MAX_RESPONSE_LEN {
+            return 0;
+        }
+        staging[len] = b'=';
+        len += 1;
+        match calculator.compute() {
+            Ok(result) => len += format_i64(result, &mut staging[len..]),
+            Err(_) => {
+                staging[len..len + 3].copy_from_slice(b"ERR");
+                len += 3;
             }
-            // Output carriage return and line feed
-            let _ = buffer.enqueue(13);
-            let _ = buffer.enqueue(10);
-        } else if calculator.transition(received_byte as u8 as char) {
-            // Input into calculator state machine
-            // Echo byte if valid transition
-            let _ = buffer.enqueue(received_byte);
-        };
+        }
+        staging[len] = 13; // CR
+        staging[len + 1] = 10; // LF
+        len += 2;
+    } else if calculator.transition(received as char) {
+        // Echo byte if valid transition
+        if staging.is_empty() {
+            return 0;
+        }
+        staging[0] = received;
+        len += 1;
+    }
+    len
+}
 
-        if !buffer.is_empty() {
-            usart2.cr1_disabled().modify(|_, w| w.txfnfie().set_bit());
+/// Route one received byte to the structured JSON command mode, the streaming biquad
+/// sample mode, or the digit/operator byte grammar -- in that priority order, so a
+/// `{...}` JSON line (including the `{"mode":...}` command that toggles streaming mode)
+/// is always recognized even while streaming mode is active. Writes any response into
+/// `staging` and returns the number of bytes written.
+fn process_byte(
+    calculator: &mut CalculatorStateMachine,
+    biquad: &mut Biquad,
+    json_line: &mut String<128>,
+    stream_mode: &mut bool,
+    sample: &mut SampleAccumulator,
+    byte: u8,
+    staging: &mut [u8],
+) -> usize {
+    if !json_line.is_empty() || byte == b'{' {
+        if byte == b'\n' {
+            let len = protocol::handle_line(json_line, biquad, stream_mode, staging);
+            json_line.clear();
+            len
+        } else {
+            let _ = json_line.push(byte as char);
+            0
+        }
+    } else if *stream_mode {
+        match sample.push(byte) {
+            Some(x0) => build_sample_response(biquad, x0, staging),
+            None => 0,
         }
+    } else {
+        build_response(calculator, byte, staging)
     }
 }
 
-fn int_to_chars(mut int: u32) -> [u8; 8] {
-    let mut chars: [u8; 8] = [0; 8];
-    for (i, power) in [10_000_000, 1_000_000, 100_000, 10_000, 1_000, 100, 10, 1]
-        .iter()
-        .enumerate()
-    {
-        let digit = int / power;
-        chars[i] = (digit + 48) as u8;
-        int -= digit * power;
+/// Point USART2's TX GPDMA channel at `bytes` and kick off a one-shot transfer.
+fn usart2_gpdma_tx(gpdma1: &GPDMA1, usart2: &USART2, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    gpdma1.ch1().ccr().modify(|_, w| w.en().clear_bit());
+    gpdma1
+        .ch1()
+        .csar()
+        .write(|w| unsafe { w.sa().bits(bytes.as_ptr() as u32) });
+    gpdma1
+        .ch1()
+        .cbr1()
+        .write(|w| unsafe { w.bndt().bits(bytes.len() as u16) });
+    gpdma1.ch1().ccr().modify(|_, w| w.en().set_bit());
+    usart2.cr3().modify(|_, w| w.dmat().set_bit());
+}
+
+/// Re-arm SPI1's RX GPDMA channel for another full pass over `RX_RING`, whose base
+/// address is `rx_ring_ptr`. GPDMA live-updates `CDAR` to track the current write
+/// pointer as a transfer progresses, so after a full pass it points at the end of
+/// `RX_RING`, not its start -- `cdar` has to be written back to the base address on
+/// every re-arm or the next pass would write past the end of the buffer.
+fn spi1_gpdma_rx_rearm(gpdma1: &GPDMA1, rx_ring_ptr: *mut u8) {
+    gpdma1.ch0().ccr().modify(|_, w| w.en().clear_bit());
+    gpdma1.ch0().cdar().write(|w| w.da().bits(rx_ring_ptr as u32));
+    gpdma1.ch0().cbr1().write(|w| unsafe { w.bndt().bits(RX_RING_LEN as u16) });
+    gpdma1.ch0().ccr().modify(|_, w| w.en().set_bit());
+}
+
+/// Pull whatever SPI1 has written into `RX_RING` since the last call, run it through
+/// `process_byte`, and kick a response out over USART2 -- but only if channel 1 isn't
+/// still busy draining a previous one (tracked by `tx_busy` rather than polled, since
+/// SPI1 RX refills much faster than USART2 drains at 9600 baud and a one-shot channel's
+/// own enable bit can't be spun on without masking interrupts for as long as it takes to
+/// drain a full response). Shared by `GPDMA1_CH0` (SPI1 RX) and `GPDMA1_CH1` (USART2 TX
+/// complete), so a response that arrived while channel 1 was busy goes out as soon as it
+/// frees up instead of waiting for the next SPI1 RX half/full-transfer interrupt.
+#[allow(clippy::too_many_arguments)]
+fn drain_rx_ring(
+    gpdma1: &GPDMA1,
+    usart2: &USART2,
+    calculator: &mut CalculatorStateMachine,
+    biquad: &mut Biquad,
+    json_line: &mut String<128>,
+    stream_mode: &mut bool,
+    sample: &mut SampleAccumulator,
+    rx_ring: &mut DmaRingBuffer<RX_RING_LEN>,
+    tx_busy: &mut bool,
+    staging: &mut [u8],
+) {
+    if *tx_busy {
+        return;
+    }
+
+    let remaining = gpdma1.ch0().cbr1().read().bndt().bits();
+    let bytes = rx_ring.take_slice(remaining);
+
+    let mut len = 0;
+    for &byte in bytes {
+        len += process_byte(calculator, biquad, json_line, stream_mode, sample, byte, &mut staging[len..]);
+    }
+    if len > 0 {
+        usart2_gpdma_tx(gpdma1, usart2, &staging[..len]);
+        *tx_busy = true;
     }
-    chars
+}
+
+#[interrupt]
+fn GPDMA1_CH0() {
+    with(|cs| {
+        let gpdma1 = GPDMA1_PERIPHERAL.borrow(cs).borrow();
+        let gpdma1 = gpdma1.as_ref().unwrap();
+        let usart2 = USART2_PERIPHERAL.borrow(cs).borrow();
+        let usart2 = usart2.as_ref().unwrap();
+        let mut calculator = CALCULATOR.borrow(cs).borrow_mut();
+        let mut biquad = BIQUAD.borrow(cs).borrow_mut();
+        let mut rx_ring = RX_RING.borrow(cs).borrow_mut();
+        let mut json_line = JSON_LINE.borrow(cs).borrow_mut();
+        let mut stream_mode = STREAM_MODE.borrow(cs).borrow_mut();
+        let mut sample = SAMPLE.borrow(cs).borrow_mut();
+        let mut staging = TX_STAGING.borrow(cs).borrow_mut();
+        let staging: &mut [u8] = &mut staging[..];
+        let mut tx_busy = TX_BUSY.borrow(cs).borrow_mut();
+
+        let full_transfer = gpdma1.ch0().csr().read().tcf().bit_is_set();
+        gpdma1.ch0().cfcr().write(|w| w.tcf().set_bit().htf().set_bit());
+
+        drain_rx_ring(
+            gpdma1,
+            usart2,
+            &mut calculator,
+            &mut biquad,
+            &mut json_line,
+            &mut stream_mode,
+            &mut sample,
+            &mut rx_ring,
+            &mut tx_busy,
+            staging,
+        );
+
+        if full_transfer {
+            spi1_gpdma_rx_rearm(gpdma1, rx_ring.as_mut_ptr());
+        }
+    });
+}
+
+#[interrupt]
+fn GPDMA1_CH1() {
+    with(|cs| {
+        let gpdma1 = GPDMA1_PERIPHERAL.borrow(cs).borrow();
+        let gpdma1 = gpdma1.as_ref().unwrap();
+        let usart2 = USART2_PERIPHERAL.borrow(cs).borrow();
+        let usart2 = usart2.as_ref().unwrap();
+        let mut calculator = CALCULATOR.borrow(cs).borrow_mut();
+        let mut biquad = BIQUAD.borrow(cs).borrow_mut();
+        let mut rx_ring = RX_RING.borrow(cs).borrow_mut();
+        let mut json_line = JSON_LINE.borrow(cs).borrow_mut();
+        let mut stream_mode = STREAM_MODE.borrow(cs).borrow_mut();
+        let mut sample = SAMPLE.borrow(cs).borrow_mut();
+        let mut staging = TX_STAGING.borrow(cs).borrow_mut();
+        let staging: &mut [u8] = &mut staging[..];
+        let mut tx_busy = TX_BUSY.borrow(cs).borrow_mut();
+
+        gpdma1.ch1().cfcr().write(|w| w.tcf().set_bit());
+        *tx_busy = false;
+
+        // Flush anything that arrived in RX_RING while channel 1 was busy, rather than
+        // waiting for SPI1 RX's next half/full-transfer interrupt.
+        drain_rx_ring(
+            gpdma1,
+            usart2,
+            &mut calculator,
+            &mut biquad,
+            &mut json_line,
+            &mut stream_mode,
+            &mut sample,
+            &mut rx_ring,
+            &mut tx_busy,
+            staging,
+        );
+    });
 }
 
 #[entry]
@@ -187,10 +570,13 @@ fn main() -> ! {
 
     let dp = Peripherals::take().unwrap();
 
-    // Enable peripheral clocks - GPIOA, USART2, SPI1
+    clock_setup(&dp.RCC, &dp.PWR, &dp.FLASH);
+
+    // Enable peripheral clocks - GPIOA, USART2, SPI1, GPDMA1
     dp.RCC.ahb2enr1().write(|w| w.gpioaen().enabled());
     dp.RCC.apb1enr1().write(|w| w.usart2en().enabled());
     dp.RCC.apb2enr().write(|w| w.spi1en().enabled());
+    dp.RCC.ahb1enr().write(|w| w.gpdma1en().set_bit());
 
     // USART2: A2 (TX), A3 (RX) as AF 7
     // SPI1: A4 (NSS), A5 (SCK), A6 (MISO), A7 (MOSI) as AF 5
@@ -237,27 +623,56 @@ fn main() -> ! {
             .af5()
     });
 
-    // USART2: Configure baud rate 9600
-    dp.USART2.brr().write(|w| unsafe { w.bits(417) }); // 4Mhz / 9600 approx. 417
+    // USART2: Configure baud rate 9600 from the PLL-derived SYSCLK, rather than the old
+    // 4 MHz-assuming magic 417
+    dp.USART2
+        .brr()
+        .write(|w| unsafe { w.bits((SYSCLK_HZ / 9600) as u16) });
 
-    // SPI1: Enable receive packet interrupt
-    dp.SPI1.spi_ier().write(|w| w.rxpie().set_bit());
+    // SPI1: enable RX DMA requests. No MSTR/baud-rate prescaler here -- this side stays
+    // in the default slave mode and shifts in whatever clock the controller's SPI1
+    // drives, so there's no local divider to derive from SYSCLK_HZ.
+    dp.SPI1.spi_cfg1().write(|w| w.rxdmaen().set_bit());
     dp.SPI1.spi_cr1().write(|w| w.spe().set_bit());
 
-    // Enable USART, transmitter and RXNE interrupt
-    dp.USART2
-        .cr1_disabled()
-        .write(|w| w.te().set_bit().ue().set_bit());
-
-    unsafe {
-        BUFFER = Some(Queue::default());
-        CALCULATOR = Some(CalculatorStateMachine::default());
-        // Unmask global interrupts
-        cortex_m::peripheral::NVIC::unmask(Interrupt::SPI1);
-        cortex_m::peripheral::NVIC::unmask(Interrupt::USART2);
-        SPI1_PERIPHERAL = Some(dp.SPI1);
-        USART2_PERIPHERAL = Some(dp.USART2);
-    }
+    // Enable USART2 transmitter
+    dp.USART2.cr1_disabled().write(|w| w.te().set_bit().ue().set_bit());
+
+    with(|cs| {
+        let rx_ring_ptr = RX_RING.borrow(cs).borrow_mut().as_mut_ptr();
+
+        // GPDMA1 channel 0: SPI1_RX -> RX_RING, byte-sized, half/full interrupts
+        dp.GPDMA1.ch0().ctr1().write(|w| w.sdw().bits(0).ddw().bits(0).dinc().set_bit());
+        dp.GPDMA1.ch0().ctr2().write(|w| unsafe { w.reqsel().bits(6) }); // SPI1_RX
+        dp.GPDMA1
+            .ch0()
+            .csar()
+            .write(|w| w.sa().bits(dp.SPI1.spi_rxdr().as_ptr() as u32));
+        dp.GPDMA1.ch0().cdar().write(|w| w.da().bits(rx_ring_ptr as u32));
+        dp.GPDMA1.ch0().cbr1().write(|w| unsafe { w.bndt().bits(RX_RING_LEN as u16) });
+        dp.GPDMA1
+            .ch0()
+            .ccr()
+            .write(|w| w.htie().set_bit().tcie().set_bit().en().set_bit());
+
+        // GPDMA1 channel 1: TX_STAGING -> USART2_TX, byte-sized, one-shot, re-armed per response
+        dp.GPDMA1.ch1().ctr1().write(|w| w.sdw().bits(0).ddw().bits(0).sinc().set_bit());
+        dp.GPDMA1.ch1().ctr2().write(|w| unsafe { w.reqsel().bits(8) }); // USART2_TX
+        dp.GPDMA1
+            .ch1()
+            .cdar()
+            .write(|w| w.da().bits(dp.USART2.tdr().as_ptr() as u32));
+        dp.GPDMA1.ch1().ccr().write(|w| w.tcie().set_bit());
+
+        // Unmask NVIC interrupts
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(Interrupt::GPDMA1_CH0);
+            cortex_m::peripheral::NVIC::unmask(Interrupt::GPDMA1_CH1);
+        }
+        GPDMA1_PERIPHERAL.borrow(cs).replace(Some(dp.GPDMA1));
+        SPI1_PERIPHERAL.borrow(cs).replace(Some(dp.SPI1));
+        USART2_PERIPHERAL.borrow(cs).replace(Some(dp.USART2));
+    });
 
     #[allow(clippy::empty_loop)]
     loop {}
@@ -0,0 +1,153 @@
+//! Structured JSON command protocol, as an alternative to the fixed digit/operator byte
+//! grammar `CalculatorStateMachine` understands. A request is a single newline-terminated
+//! JSON object: `{"op":"add","args":[1,2,3]}`, `{"eval":"12+7"}`, `{"sample":1234}` to run
+//! one sample through the streaming biquad filter, `{"coeffs":[b0,b1,b2,a1,a2]}` to load
+//! new filter coefficients at runtime, or `{"mode":"stream"}`/`{"mode":"calc"}` to switch
+//! whether raw (non-JSON) bytes on the link are treated as comma/newline-terminated
+//! integer samples for the biquad filter rather than the digit/operator grammar. This
+//! decouples the wire format from the state machine and reports overflow, divide-by-zero,
+//! and parse errors as distinct statuses, where the byte grammar only answers `ERR`.
+
+use crate::biquad::{Biquad, Coefficients};
+use crate::CalculatorError;
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct Request<'a> {
+    op: Option<&'a str>,
+    args: Option<Vec<i64, 8>>,
+    eval: Option<&'a str>,
+    sample: Option<i32>,
+    coeffs: Option<Coefficients>,
+    mode: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+pub struct Response {
+    result: i64,
+    status: Status,
+}
+
+#[derive(Serialize)]
+pub enum Status {
+    #[serde(rename = "ok")]
+    Ok,
+    #[serde(rename = "overflow")]
+    Overflow,
+    #[serde(rename = "divide_by_zero")]
+    DivideByZero,
+    #[serde(rename = "parse_error")]
+    ParseError,
+}
+
+impl Response {
+    fn ok(result: i64) -> Self {
+        Self {
+            result,
+            status: Status::Ok,
+        }
+    }
+
+    fn err(status: Status) -> Self {
+        Self { result: 0, status }
+    }
+}
+
+/// Fold `op` across `args` using `i64` checked arithmetic, reporting overflow and
+/// divide-by-zero instead of wrapping or saturating.
+fn apply_op(op: &str, args: &[i64]) -> Response {
+    if !matches!(op, "add" | "sub" | "mul" | "div") {
+        return Response::err(Status::ParseError);
+    }
+    let Some((&first, rest)) = args.split_first() else {
+        return Response::err(Status::ParseError);
+    };
+    let mut saw_zero_divisor = false;
+    let result = rest.iter().try_fold(first, |acc, &arg| match op {
+        "add" => acc.checked_add(arg),
+        "sub" => acc.checked_sub(arg),
+        "mul" => acc.checked_mul(arg),
+        "div" => {
+            if arg == 0 {
+                saw_zero_divisor = true;
+                None
+            } else {
+                acc.checked_div(arg)
+            }
+        }
+        _ => None,
+    });
+    match result {
+        Some(result) => Response::ok(result),
+        None if saw_zero_divisor => Response::err(Status::DivideByZero),
+        None if matches!(op, "add" | "sub" | "mul" | "div") => Response::err(Status::Overflow),
+        None => Response::err(Status::ParseError),
+    }
+}
+
+/// Map a `CalculatorStateMachine` failure cause onto the matching `Status`.
+fn status_for_error(error: CalculatorError) -> Response {
+    match error {
+        CalculatorError::Overflow => Response::err(Status::Overflow),
+        CalculatorError::DivideByZero => Response::err(Status::DivideByZero),
+        CalculatorError::ParseError => Response::err(Status::ParseError),
+    }
+}
+
+/// Evaluate a full expression with operator precedence and parentheses by streaming
+/// `expr` character-by-character through the same `CalculatorStateMachine` the digit/
+/// operator byte grammar uses.
+fn eval_expr(expr: &str) -> Response {
+    let mut calculator = crate::CalculatorStateMachine::default();
+    for ch in expr.chars() {
+        if ch.is_whitespace() {
+            continue;
+        }
+        if !calculator.transition(ch) {
+            return match calculator.error() {
+                Some(error) => status_for_error(error),
+                None => Response::err(Status::ParseError),
+            };
+        }
+    }
+    match calculator.compute() {
+        Ok(result) => Response::ok(result),
+        Err(error) => status_for_error(error),
+    }
+}
+
+/// Parse `line` (a single JSON object, without the trailing newline) as a `Request`,
+/// dispatch it, and serialize the `Response` plus a trailing `\n` into `out`. Returns
+/// the number of bytes written, or 0 if `out` doesn't have room for the response plus
+/// its trailing newline.
+pub fn handle_line(line: &str, biquad: &mut Biquad, stream_mode: &mut bool, out: &mut [u8]) -> usize {
+    let response = match serde_json_core::from_str::<Request>(line) {
+        Ok((request, _)) => {
+            if let Some(mode) = request.mode {
+                *stream_mode = mode == "stream";
+                Response::ok(0)
+            } else if let Some(coeffs) = request.coeffs {
+                biquad.load_coefficients(coeffs);
+                Response::ok(0)
+            } else if let Some(x0) = request.sample {
+                Response::ok(biquad.process(x0) as i64)
+            } else if let Some(expr) = request.eval {
+                eval_expr(expr)
+            } else if let Some(op) = request.op {
+                apply_op(op, request.args.as_deref().unwrap_or(&[]))
+            } else {
+                Response::err(Status::ParseError)
+            }
+        }
+        Err(_) => Response::err(Status::ParseError),
+    };
+
+    match serde_json_core::to_slice(&response, out) {
+        Ok(len) if len < out.len() => {
+            out[len] = b'\n';
+            len + 1
+        }
+        _ => 0,
+    }
+}